@@ -1,14 +1,27 @@
 extern crate time;
+extern crate rand;
+extern crate sha2;
+extern crate aes_gcm;
+extern crate x25519_dalek;
 
 use std::io::{Read, Write, Seek};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use aes_gcm::aead::{Aead, NewAead};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey};
 
-const HELP_TEXT: &'static str = "netcp (send,receive) ipaddress[:port] filename";
+const HELP_TEXT: &'static str = "netcp send ipaddress[:port] file_or_dir...\n       netcp receive ipaddress[:port] accesskey";
 const CALL_SIGN: &'static str = "netcp v0.1";
 const MSG_AGREE: &'static str = "AGREE   ";
 const MSG_DISAGREE: &'static str = "DISAGREE";
 const MSG_FILE: &'static str = "FILE";
 const MSG_END: &'static str = "END ";
 const TIMEOUT: i64 = 800;
+const ACCESS_KEY_LEN: usize = 8;
+const ACCESS_KEY_CHARS: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+const DEFAULT_BUFFER_SIZE: usize = 512;
+const MAX_BUFFER_SIZE: usize = 64 * 1024 * 1024;
+const MAX_RECONNECT_ATTEMPTS: u32 = 10;
 
 macro_rules! error {
     ($($tt:tt)*) => {{
@@ -20,6 +33,10 @@ macro_rules! error {
     }}
 }
 
+//peer went away (timeout or broken socket); recoverable by reconnecting
+struct Disconnected;
+type NetResult<T> = Result<T, Disconnected>;
+
 fn main() {
     //get commandline arguments
     let args: Vec<String> = std::env::args().collect();
@@ -41,16 +58,43 @@ fn main() {
                 error!("Too few arguments");
             }
 
-            send(&args[2], &args[3..]);
+            //pull an optional "--buffer-size <bytes>" option out of the filename list
+            let mut buffer_size = DEFAULT_BUFFER_SIZE;
+            let mut file_names: Vec<String> = Vec::new();
+            let mut i = 3;
+            while i < args.len() {
+                if args[i] == "--buffer-size" {
+                    if i + 1 >= args.len() {
+                        error!("--buffer-size needs a value");
+                    }
+
+                    buffer_size = match args[i + 1].parse::<usize>() {
+                        Err(_) => error!("Invalid --buffer-size value"),
+                        Ok(buffer_size) => buffer_size
+                    };
+
+                    i += 2;
+                }
+                else {
+                    file_names.push(args[i].clone());
+                    i += 1;
+                }
+            }
+
+            if file_names.is_empty() {
+                error!("No files given");
+            }
+
+            send(&args[2], &file_names, buffer_size);
         }
 
         "receive" => {
-            //receiving needs exactly 3 args (appl. name, "receive", address)
-            if args.len() != 3 {
+            //receiving needs exactly 4 args (appl. name, "receive", address, access key)
+            if args.len() != 4 {
                 error!("Too few or many arguments");
             }
 
-            receive(&args[2]);
+            receive(&args[2], &args[3]);
         }
 
         //Unknown parameter
@@ -60,13 +104,13 @@ fn main() {
     }
 }
 
-fn receive_data(stream: &mut std::net::TcpStream, buf: &mut [u8]) {
+fn receive_data(stream: &mut std::net::TcpStream, buf: &mut [u8]) -> NetResult<()> {
     let mut begin = time::get_time();
     let mut received_bytes: usize = 0;
 
     while received_bytes < buf.len() && (begin - time::get_time()).num_milliseconds() < TIMEOUT {
         let bytes = match stream.read(&mut buf[received_bytes..]) {
-            Err(_) => error!("Connection lost"),
+            Err(_) => return Err(Disconnected),
             Ok(bytes) => bytes
         };
 
@@ -78,17 +122,19 @@ fn receive_data(stream: &mut std::net::TcpStream, buf: &mut [u8]) {
     }
 
     if received_bytes < buf.len() {
-        error!("Connection lost (timeout)");
+        return Err(Disconnected);
     }
+
+    return Ok(());
 }
 
-fn send_data(stream: &mut std::net::TcpStream, data: &[u8]) {
+fn send_data(stream: &mut std::net::TcpStream, data: &[u8]) -> NetResult<()> {
     let mut begin = time::get_time();
     let mut sended_bytes: usize = 0;
 
     while sended_bytes < data.len() && (begin - time::get_time()).num_milliseconds() < TIMEOUT {
         let bytes = match stream.write(&data[sended_bytes..]) {
-            Err(_) => error!("Connection lost"),
+            Err(_) => return Err(Disconnected),
             Ok(bytes) => bytes
         };
 
@@ -100,52 +146,46 @@ fn send_data(stream: &mut std::net::TcpStream, data: &[u8]) {
     }
 
     if sended_bytes < data.len() {
-        error!("Connection lost (timeout)");
+        return Err(Disconnected);
     }
+
+    return Ok(());
 }
 
-fn send_u64(stream: &mut std::net::TcpStream, num: u64) {
+fn send_u64(stream: &mut std::net::TcpStream, num: u64) -> NetResult<()> {
     let data = num.to_le();
     let buf: &[u8] = unsafe { std::mem::transmute::<&u64, &[u8; 8]>(&data) };
-    send_data(stream, &buf);
+    return send_data(stream, &buf);
 }
 
-fn receive_u64(stream: &mut std::net::TcpStream) -> u64 {
+fn receive_u64(stream: &mut std::net::TcpStream) -> NetResult<u64> {
     let mut num = 0u64;
-    receive_data(stream, unsafe { std::mem::transmute::<&mut u64, &mut [u8; 8]>(&mut num) });
-    return u64::from_le(num);
+    receive_data(stream, unsafe { std::mem::transmute::<&mut u64, &mut [u8; 8]>(&mut num) })?;
+    return Ok(u64::from_le(num));
 }
 
-fn send_string(stream: &mut std::net::TcpStream, string: &str) {
-    let size = string.len() as u64;
-    send_u64(stream, size);
-    send_data(stream, string.as_bytes());
-}
+//compares two byte strings without branching on the position of the first
+//mismatch, so a wrong guess can't be narrowed down by timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
 
-fn receive_string(stream: &mut std::net::TcpStream) -> String {
-    let size = receive_u64(stream);
-    let mut vec = vec![0u8; size as usize];
-    receive_data(stream, &mut vec[..] );
-    let string = match String::from_utf8(vec) {
-        Err(_) => error!("Couldn't convert bytes to string"),
-        Ok(string) => string
-    };
-    return string;
+    let mut diff = 0u8;
+    for i in 0..a.len() {
+        diff |= a[i] ^ b[i];
+    }
+
+    return diff == 0;
 }
 
-fn check_agreement(stream: &mut std::net::TcpStream) -> bool {
-    let mut vec = vec![0u8; MSG_AGREE.len()];
-    receive_data(stream, &mut vec[..]);
+fn generate_access_key() -> String {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
 
-    if compare_byte_array(&vec[..], MSG_AGREE.as_bytes()) {
-        return true;
-    }
-    else if compare_byte_array(&vec[..], MSG_DISAGREE.as_bytes()) {
-        return false;
-    }
-    else {
-        error!("Invalid protocol");
-    }
+    return (0..ACCESS_KEY_LEN)
+        .map(|_| ACCESS_KEY_CHARS[rng.gen_range(0..ACCESS_KEY_CHARS.len())] as char)
+        .collect();
 }
 
 fn compare_byte_array(a: &[u8], b: &[u8]) -> bool {
@@ -180,33 +220,328 @@ fn get_filesize(file: &mut std::fs::File) -> u64 {
     return size;
 }
 
-fn send(address: &String, file_names: &[String]) {
+//rebuilds the hash over the first `len` bytes already on disk; leaves the cursor at `len`
+fn hash_prefix(file: &mut std::fs::File, len: u64, buf: &mut [u8]) -> Sha256 {
+    if let Err(_) = file.seek(std::io::SeekFrom::Start(0)) {
+        error!("File seeking failed");
+    }
+
+    let mut hasher = Sha256::new();
+    let mut i = 0u64;
+    while i < len {
+        let chunk = std::cmp::min(buf.len() as u64, len - i) as usize;
+
+        if let Err(_) = file.read_exact(&mut buf[..chunk]) {
+            error!("Couldn't read from file");
+        }
+
+        hasher.update(&buf[..chunk]);
+        i += chunk as u64;
+    }
+
+    return hasher;
+}
+
+//nonce is a per-session counter in the low 8 bytes, rest zero
+fn nonce_from_counter(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    let counter_le = counter.to_le();
+    let counter_bytes: &[u8; 8] = unsafe { std::mem::transmute(&counter_le) };
+    nonce[..8].copy_from_slice(counter_bytes);
+    return nonce;
+}
+
+fn encrypt_frame(key: &[u8; 32], nonce_counter: u64, plaintext: &[u8]) -> Vec<u8> {
+    let cipher = Aes256Gcm::new(Key::from_slice(key));
+    let nonce = nonce_from_counter(nonce_counter);
+
+    match cipher.encrypt(Nonce::from_slice(&nonce), plaintext) {
+        Err(_) => error!("Encryption failed"),
+        Ok(ciphertext) => ciphertext
+    }
+}
+
+fn decrypt_frame(key: &[u8; 32], nonce_counter: u64, ciphertext: &[u8]) -> Vec<u8> {
+    let cipher = Aes256Gcm::new(Key::from_slice(key));
+    let nonce = nonce_from_counter(nonce_counter);
+
+    match cipher.decrypt(Nonce::from_slice(&nonce), ciphertext) {
+        Err(_) => error!("Integrity check failed"),
+        Ok(plaintext) => plaintext
+    }
+}
+
+//derives a per-direction key from the shared secret so both directions never encrypt under the same key
+fn derive_directional_key(shared_secret: &x25519_dalek::SharedSecret, label: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(shared_secret.as_bytes());
+    hasher.update(label);
+    let digest = hasher.finalize();
+
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&digest);
+    return key;
+}
+
+//largest ciphertext frame we're willing to allocate for, to bound a spliced-in length
+const MAX_FRAME_LEN: u64 = (MAX_BUFFER_SIZE as u64) + 4096;
+
+//wraps a TcpStream once a session key is agreed; messages travel as authenticated AES-256-GCM frames
+struct Channel {
+    stream: std::net::TcpStream,
+    send_key: [u8; 32],
+    recv_key: [u8; 32],
+    send_nonce: u64,
+    recv_nonce: u64,
+}
+
+impl Channel {
+    //ephemeral X25519 key exchange over `stream`, then derives one key per direction
+    fn establish(mut stream: std::net::TcpStream, is_server: bool) -> NetResult<Channel> {
+        let my_secret = EphemeralSecret::new(rand::rngs::OsRng);
+        let my_public = PublicKey::from(&my_secret);
+
+        send_data(&mut stream, my_public.as_bytes())?;
+
+        let mut their_bytes = [0u8; 32];
+        receive_data(&mut stream, &mut their_bytes)?;
+        let their_public = PublicKey::from(their_bytes);
+
+        let shared_secret = my_secret.diffie_hellman(&their_public);
+
+        let client_to_server = derive_directional_key(&shared_secret, b"netcp client-to-server");
+        let server_to_client = derive_directional_key(&shared_secret, b"netcp server-to-client");
+
+        let (send_key, recv_key) = if is_server {
+            (server_to_client, client_to_server)
+        }
+        else {
+            (client_to_server, server_to_client)
+        };
+
+        return Ok(Channel { stream: stream, send_key: send_key, recv_key: recv_key, send_nonce: 0, recv_nonce: 0 });
+    }
+
+    fn send_data(&mut self, data: &[u8]) -> NetResult<()> {
+        let ciphertext = encrypt_frame(&self.send_key, self.send_nonce, data);
+        self.send_nonce += 1;
+
+        send_u64(&mut self.stream, ciphertext.len() as u64)?;
+        return send_data(&mut self.stream, &ciphertext);
+    }
+
+    fn receive_data(&mut self, buf: &mut [u8]) -> NetResult<()> {
+        let len = receive_u64(&mut self.stream)?;
+        if len > MAX_FRAME_LEN {
+            error!("Frame length exceeds the negotiated maximum");
+        }
+        let mut ciphertext = vec![0u8; len as usize];
+        receive_data(&mut self.stream, &mut ciphertext[..])?;
+
+        let plaintext = decrypt_frame(&self.recv_key, self.recv_nonce, &ciphertext);
+        self.recv_nonce += 1;
+
+        if plaintext.len() != buf.len() {
+            error!("Invalid protocol");
+        }
+        buf.copy_from_slice(&plaintext);
+        return Ok(());
+    }
+
+    fn send_u64(&mut self, num: u64) -> NetResult<()> {
+        let data = num.to_le();
+        let buf: &[u8] = unsafe { std::mem::transmute::<&u64, &[u8; 8]>(&data) };
+        return self.send_data(buf);
+    }
+
+    fn receive_u64(&mut self) -> NetResult<u64> {
+        let mut num = 0u64;
+        self.receive_data(unsafe { std::mem::transmute::<&mut u64, &mut [u8; 8]>(&mut num) })?;
+        return Ok(u64::from_le(num));
+    }
+
+    fn send_string(&mut self, string: &str) -> NetResult<()> {
+        let size = string.len() as u64;
+        self.send_u64(size)?;
+        return self.send_data(string.as_bytes());
+    }
+
+    fn receive_string(&mut self) -> NetResult<String> {
+        let size = self.receive_u64()?;
+        let mut vec = vec![0u8; size as usize];
+        self.receive_data(&mut vec[..])?;
+        let string = match String::from_utf8(vec) {
+            Err(_) => error!("Couldn't convert bytes to string"),
+            Ok(string) => string
+        };
+        return Ok(string);
+    }
+}
+
+fn check_agreement_channel(channel: &mut Channel) -> NetResult<bool> {
+    let mut vec = vec![0u8; MSG_AGREE.len()];
+    channel.receive_data(&mut vec[..])?;
+
+    if compare_byte_array(&vec[..], MSG_AGREE.as_bytes()) {
+        return Ok(true);
+    }
+    else if compare_byte_array(&vec[..], MSG_DISAGREE.as_bytes()) {
+        return Ok(false);
+    }
+    else {
+        error!("Invalid protocol");
+    }
+}
+
+//expands each argument into (relative_path, absolute_path) manifest entries, recursing into directories
+fn build_manifest(work_dir: &std::path::Path, file_names: &[String]) -> Vec<(String, std::path::PathBuf)> {
+    let mut manifest = Vec::new();
+
+    for file_name in file_names {
+        let root_path = work_dir.join(file_name);
+
+        let metadata = match std::fs::metadata(&root_path) {
+            Err(_) => error!("File doesn't exist or is not accessible ({})", root_path.display()),
+            Ok(metadata) => metadata
+        };
+
+        if metadata.is_dir() {
+            walk_dir(&root_path, file_name, &mut manifest);
+        }
+        else {
+            manifest.push((file_name.clone(), root_path));
+        }
+    }
+
+    return manifest;
+}
+
+//joins a manifest-supplied relative path onto work_dir, rejecting anything that could escape it
+fn resolve_manifest_path(work_dir: &std::path::Path, rel_path: &str) -> std::path::PathBuf {
+    let path = std::path::Path::new(rel_path);
+
+    for component in path.components() {
+        match component {
+            std::path::Component::Normal(_) | std::path::Component::CurDir => {}
+            _ => error!("Refusing to write outside the working directory ({})", rel_path)
+        }
+    }
+
+    return work_dir.join(path);
+}
+
+fn walk_dir(dir: &std::path::Path, rel_prefix: &str, manifest: &mut Vec<(String, std::path::PathBuf)>) {
+    let entries = match std::fs::read_dir(dir) {
+        Err(_) => error!("Couldn't read directory ({})", dir.display()),
+        Ok(entries) => entries
+    };
+
+    for entry_res in entries {
+        let entry = match entry_res {
+            Err(_) => error!("Couldn't read directory entry in ({})", dir.display()),
+            Ok(entry) => entry
+        };
+
+        let entry_name = match entry.file_name().into_string() {
+            Err(_) => error!("Couldn't convert filename to utf8"),
+            Ok(entry_name) => entry_name
+        };
+
+        //wire format always uses '/' as the separator, independent of the host OS
+        let rel_path = format!("{}/{}", rel_prefix, entry_name);
+        let entry_path = entry.path();
+
+        let file_type = match entry.file_type() {
+            Err(_) => error!("Couldn't determine file type ({})", entry_path.display()),
+            Ok(file_type) => file_type
+        };
+
+        if file_type.is_dir() {
+            walk_dir(&entry_path, &rel_path, manifest);
+        }
+        else {
+            manifest.push((rel_path, entry_path));
+        }
+    }
+}
+
+//minimum gap between progress redraws, so fast transfers don't spam stdout
+const PROGRESS_INTERVAL_MS: i64 = 200;
+
+//prints an in-place progress line with bytes transferred, percentage and transfer rate
+struct ProgressReporter {
+    label: String,
+    total: u64,
+    transferred: u64,
+    window_start: time::Timespec,
+    window_bytes: u64,
+    last_print: time::Timespec,
+}
+
+impl ProgressReporter {
+    fn new(label: &str, total: u64, already_transferred: u64) -> ProgressReporter {
+        let now = time::get_time();
+        ProgressReporter {
+            label: label.to_string(),
+            total: total,
+            transferred: already_transferred,
+            window_start: now,
+            window_bytes: 0,
+            last_print: now,
+        }
+    }
+
+    fn advance(&mut self, bytes: u64) {
+        self.transferred += bytes;
+        self.window_bytes += bytes;
+
+        let now = time::get_time();
+        if (now - self.last_print).num_milliseconds() < PROGRESS_INTERVAL_MS {
+            return;
+        }
+
+        self.redraw(now);
+        self.window_start = now;
+        self.window_bytes = 0;
+        self.last_print = now;
+    }
+
+    fn redraw(&self, now: time::Timespec) {
+        let elapsed_secs = (now - self.window_start).num_milliseconds() as f64 / 1000.0;
+        let rate_mib_s = if elapsed_secs > 0.0 { (self.window_bytes as f64 / (1024.0 * 1024.0)) / elapsed_secs } else { 0.0 };
+        let percent = if self.total > 0 { (self.transferred as f64 / self.total as f64) * 100.0 } else { 100.0 };
+
+        print!("\r{}: {}/{} bytes ({:.1}%) at {:.2} MiB/s   ", self.label, self.transferred, self.total, percent, rate_mib_s);
+        let _ = std::io::stdout().flush();
+    }
+
+    //blanks out the progress line so the caller can print a final status cleanly
+    fn clear(&self) {
+        print!("\r{}\r", " ".repeat(self.label.len() + 48));
+        let _ = std::io::stdout().flush();
+    }
+}
+
+fn send(address: &String, file_names: &[String], buffer_size: usize) {
     //1. check file accessibilities
     //2. check address
-    //3. connect with client
-    //4. send files sequential
+    //3. connect with client, retrying/resyncing on drops
+    //4. establish encrypted channel, negotiate a transfer buffer size
+    //5. send files sequential
 
-    let mut buf = [0u8; 512];
+    //generate the access key clients have to prove knowledge of before anything is served
+    let access_key = generate_access_key();
+    println!("Access key: {}", access_key);
 
-    //1. check file existences
+    //1. check file existences and build the transfer manifest
     //get working directory to find correct files
     let work_dir = match std::env::current_dir() {
         Err(_) => error!("Couldn't find working directory"),
         Ok(work_dir) => work_dir
     };
 
-    //join working directory and file_names to get absolute file addresses in a vector
-    let mut file_addrs: Vec<std::path::PathBuf> = Vec::with_capacity(file_names.len());
-    for file_name in file_names {
-        file_addrs.push(work_dir.join(file_name));
-    }
-
-    //open and close files to check accessibility
-    for file_addr in &file_addrs {
-        if let Err(_) = std::fs::File::open(&file_addr) {
-            error!("File doesn't exist or is not accessible ({})", file_addr.display());
-        }
-    }
+    //walk file_names, expanding directories into their files with relative paths preserved
+    let manifest = build_manifest(&work_dir, file_names);
 
     //2. check address and bind listener
     let listener = match std::net::TcpListener::bind(&address[..]) {
@@ -214,26 +549,75 @@ fn send(address: &String, file_names: &[String]) {
         Ok(listener) => listener
     };
 
-    //3. connect with client
-    print!("Waiting for client..."); let _ = std::io::stdout().flush();
-    let (mut stream, client_addr) = match listener.accept() {
-        Err(e) => error!("{}", e),
-        Ok(stream) => stream
-    };
+    //3. accept clients, reconnecting the same transfer if the connection drops mid-way
+    let mut reconnects = 0u32;
+    loop {
+        print!("Waiting for client..."); let _ = std::io::stdout().flush();
+        let (stream, client_addr) = match listener.accept() {
+            Err(e) => error!("{}", e),
+            Ok(stream) => stream
+        };
+        println!("connected with {}.", client_addr); let _ = std::io::stdout().flush();
+
+        match run_send_session(stream, &access_key, buffer_size, &manifest) {
+            Ok(SendOutcome::Completed) => break,
+            Ok(SendOutcome::RejectedAccessKey) => {
+                println!("Client provided a wrong access key, waiting for another client...");
+            }
+            Err(Disconnected) => {
+                reconnects += 1;
+                if reconnects > MAX_RECONNECT_ATTEMPTS {
+                    error!("Too many reconnect attempts, giving up");
+                }
+                println!("Connection lost, waiting for the client to resume...");
+            }
+        }
+    }
+}
 
-    //3. check if client send correct CALL_SIGN
-    receive_data(&mut stream, &mut buf[..CALL_SIGN.len()]);
+//outcome of one run_send_session attempt that isn't a dropped connection
+enum SendOutcome {
+    Completed,
+    RejectedAccessKey,
+}
+
+//runs one connection attempt's worth of the send side; caller re-accepts and retries on Err(Disconnected)
+fn run_send_session(mut stream: std::net::TcpStream, access_key: &str, buffer_size: usize, manifest: &[(String, std::path::PathBuf)]) -> NetResult<SendOutcome> {
+    let mut handshake_buf = [0u8; 512];
 
-    if compare_byte_array(CALL_SIGN.as_bytes(), &buf[..CALL_SIGN.len()]) == false {
+    //check if client send correct CALL_SIGN
+    receive_data(&mut stream, &mut handshake_buf[..CALL_SIGN.len()])?;
+
+    if compare_byte_array(CALL_SIGN.as_bytes(), &handshake_buf[..CALL_SIGN.len()]) == false {
         error!("Invalid protocol");
     }
 
-    send_data(&mut stream, MSG_AGREE.as_bytes());
+    //establish the encrypted channel first, so the access key below never crosses the wire in the clear
+    let mut channel = Channel::establish(stream, true)?;
 
-    println!("connected with {}.", client_addr); let _ = std::io::stdout().flush();
+    //require the client to prove knowledge of the access key before anything else is served
+    let client_key = channel.receive_string()?;
+    if constant_time_eq(client_key.as_bytes(), access_key.as_bytes()) == false {
+        channel.send_data(MSG_DISAGREE.as_bytes())?;
+        return Ok(SendOutcome::RejectedAccessKey);
+    }
+
+    channel.send_data(MSG_AGREE.as_bytes())?;
+
+    //negotiate the transfer buffer size, capping it to stay memory-safe
+    channel.send_u64(buffer_size as u64)?;
+    let proposed_buffer_size = channel.receive_u64()? as usize;
+    if proposed_buffer_size == 0 {
+        error!("Client rejected every buffer size");
+    }
+    let agreed_buffer_size = std::cmp::min(proposed_buffer_size, MAX_BUFFER_SIZE);
+    let mut buf = vec![0u8; agreed_buffer_size];
+
+    //tell the receiver how many files (manifest entries) to expect
+    channel.send_u64(manifest.len() as u64)?;
 
     //send files
-    for file_addr in &file_addrs {
+    for &(ref filename, ref file_addr) in manifest {
 
         let mut file = match std::fs::File::open(&file_addr) {
             Err(_) => error!("Couldn't open file"),
@@ -242,78 +626,130 @@ fn send(address: &String, file_names: &[String]) {
 
         let filesize = get_filesize(&mut file);
 
-        send_data(&mut stream, MSG_FILE.as_bytes()); //send file is ready to send
-        send_u64(&mut stream, filesize); //send file size as u64
-
-        //send filename
-        let filename_os = match file_addr.file_name() {
-            None => error!("Couldn't convert filename to utf8"),
-            Some(filename_os) => filename_os
-        };
-
-        let filename = match filename_os.to_str() {
-            None => error!("Couldn't convert filename to utf8"),
-            Some(filename) => filename
-        };
-
-        send_string(&mut stream, &filename);
+        channel.send_data(MSG_FILE.as_bytes())?; //send file is ready to send
+        channel.send_u64(filesize)?; //send file size as u64
+        channel.send_string(&filename)?; //send the relative path
 
         print!("Send {}...", &filename); let _ = std::io::stdout().flush();
 
         //if client send MSG_DISAGREE, continue else send file
-        if check_agreement(&mut stream) == false {
+        if check_agreement_channel(&mut channel)? == false {
             println!("cancelled by client.");
             continue;
         }
 
+        //the receiver tells us how much of this file it already has, so a
+        //resumed connection only retransmits the missing tail
+        let offset = channel.receive_u64()?;
+
+        if offset >= filesize {
+            println!("already transferred.");
+            continue;
+        }
+
+        let mut hasher = if offset > 0 { hash_prefix(&mut file, offset, &mut buf) } else { Sha256::new() };
+        let mut progress = ProgressReporter::new(&format!("Send {}", filename), filesize, offset);
+
         //send file
-        let mut i = 0u64;
+        let mut i = offset;
         while i < (filesize-1) {
             if (filesize - i) >= buf.len() as u64 {
                 if let Err(_) = file.read(&mut buf) {
                     error!("Couldn't read from file");
                 }
-                send_data(&mut stream, &buf);
+                hasher.update(&buf);
+                channel.send_data(&buf)?;
+                progress.advance(buf.len() as u64);
                 i += buf.len() as u64;
             }
             else {
                 if let Err(_) = file.read(&mut buf[..(filesize - i) as usize]) {
                     error!("Couldn't read from file");
                 }
-                send_data(&mut stream, &buf[..(filesize - i) as usize]);
+                hasher.update(&buf[..(filesize - i) as usize]);
+                channel.send_data(&buf[..(filesize - i) as usize])?;
+                progress.advance(filesize - i);
                 i = filesize-1;
             }
         }
 
-        println!("done."); let _ = std::io::stdout().flush();
+        //trailing integrity field so the receiver can detect silent corruption
+        channel.send_data(&hasher.finalize())?;
+
+        progress.clear();
+        println!("Send {}...done.", &filename); let _ = std::io::stdout().flush();
     }
 
     //send end
-    send_data(&mut stream, MSG_END.as_bytes());
-}
+    channel.send_data(MSG_END.as_bytes())?;
 
-fn receive(address: &String) {
-    let mut buf = [0u8; 512];
+    return Ok(SendOutcome::Completed);
+}
 
+fn receive(address: &String, access_key: &String) {
     let work_dir = match std::env::current_dir() {
         Err(_) => error!("Couldn't find working directory"),
         Ok(work_dir) => work_dir
     };
 
-    let mut stream = match std::net::TcpStream::connect(&address[..]) {
-        Err(e) => error!("{}", e),
-        Ok(stream) => stream
-    };
+    let mut reconnects = 0u32;
+    loop {
+        let stream = connect_with_retry(address);
+
+        match run_receive_session(stream, access_key, &work_dir) {
+            Ok(_) => break,
+            Err(Disconnected) => {
+                reconnects += 1;
+                if reconnects > MAX_RECONNECT_ATTEMPTS {
+                    error!("Too many reconnect attempts, giving up");
+                }
+                println!("Connection lost, reconnecting...");
+            }
+        }
+    }
+}
 
-    send_data(&mut stream, CALL_SIGN.as_bytes());
+//connects to `address`, retrying with the same TIMEOUT used for stalled transfers
+fn connect_with_retry(address: &String) -> std::net::TcpStream {
+    let mut attempt = 0u32;
+    loop {
+        match std::net::TcpStream::connect(&address[..]) {
+            Ok(stream) => return stream,
+            Err(e) => {
+                attempt += 1;
+                if attempt > MAX_RECONNECT_ATTEMPTS {
+                    error!("{}", e);
+                }
+                std::thread::sleep(std::time::Duration::from_millis(TIMEOUT as u64));
+            }
+        }
+    }
+}
+
+//runs one connection attempt's worth of the receive side; caller reconnects on Err(Disconnected)
+fn run_receive_session(mut stream: std::net::TcpStream, access_key: &String, work_dir: &std::path::Path) -> NetResult<()> {
+    send_data(&mut stream, CALL_SIGN.as_bytes())?;
 
-    if check_agreement(&mut stream) == false {
-        error!("No server found");
+    let mut channel = Channel::establish(stream, false)?;
+
+    //prove knowledge of the access key only once the channel is encrypted
+    channel.send_string(&access_key)?;
+    if check_agreement_channel(&mut channel)? == false {
+        error!("Server rejected the access key");
     }
 
+    //accept the sender's proposed buffer size, capping it to stay memory-safe
+    let proposed_buffer_size = channel.receive_u64()? as usize;
+    let agreed_buffer_size = std::cmp::min(proposed_buffer_size, MAX_BUFFER_SIZE);
+    channel.send_u64(agreed_buffer_size as u64)?;
+    let mut buf = vec![0u8; agreed_buffer_size];
+
+    let file_count = channel.receive_u64()?;
+    println!("Receiving {} file(s)...", file_count);
+
     let mut msg_file = vec![0u8; MSG_FILE.len()];
     loop {
-        receive_data(&mut stream, &mut msg_file[..]);
+        channel.receive_data(&mut msg_file[..])?;
 
         if compare_byte_array(&msg_file[..], MSG_END.as_bytes()) {
             break;
@@ -322,43 +758,86 @@ fn receive(address: &String) {
             error!("Invalid protocol");
         }
 
-        let filesize = receive_u64(&mut stream);
-        let filename = receive_string(&mut stream);
+        let filesize = channel.receive_u64()?;
+        let filename = channel.receive_string()?;
+        let file_path = resolve_manifest_path(work_dir, &filename);
 
-        let file_res = std::fs::File::create(work_dir.join(&filename));
+        //recreate the manifest's subdirectories before the file lands in one
+        if let Some(parent) = file_path.parent() {
+            if let Err(_) = std::fs::create_dir_all(parent) {
+                error!("Couldn't create directory ({})", parent.display());
+            }
+        }
+
+        //bytes already on disk from an earlier, dropped connection attempt
+        let known_offset = std::fs::metadata(&file_path).map(|meta| meta.len()).unwrap_or(0);
+
+        //truncate(false) is load-bearing: it's what keeps the bytes already on disk from a resume;
+        //read(true) is needed too, since hash_prefix below reads back through this same handle
+        let file_res = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&file_path);
         match file_res {
             Err(_) => {
                 println!("Couldn't create file {}. Skip file transmition.", filename);
-                send_data(&mut stream, MSG_DISAGREE.as_bytes());
+                channel.send_data(MSG_DISAGREE.as_bytes())?;
                 continue;
             },
             _ => {}
         }
         let mut file = file_res.unwrap();
 
-        send_data(&mut stream, MSG_AGREE.as_bytes());
+        channel.send_data(MSG_AGREE.as_bytes())?;
+        channel.send_u64(known_offset)?;
+
+        if known_offset >= filesize {
+            println!("{} already fully received.", filename);
+            continue;
+        }
 
-        print!("Receive {}...", filename); let _ = std::io::stdout().flush();
+        //get file data, resuming the rolling hash over whatever we already wrote
+        let mut hasher = if known_offset > 0 { hash_prefix(&mut file, known_offset, &mut buf) } else { Sha256::new() };
+        let mut progress = ProgressReporter::new(&format!("Receive {}", filename), filesize, known_offset);
 
-        //get file data
-        let mut i = 0u64;
+        let mut i = known_offset;
         while i < (filesize-1) {
             if (filesize - i) >= buf.len() as u64 {
-                receive_data(&mut stream, &mut buf);
+                channel.receive_data(&mut buf)?;
+                hasher.update(&buf);
                 if let Err(_) = file.write(&buf) {
                     error!("Couldn't write to file");
                 }
+                progress.advance(buf.len() as u64);
                 i += buf.len() as u64;
             }
             else {
-                receive_data(&mut stream, &mut buf[..(filesize - i) as usize]);
+                channel.receive_data(&mut buf[..(filesize - i) as usize])?;
+                hasher.update(&buf[..(filesize - i) as usize]);
                 if let Err(_) = file.write(&buf[..(filesize - i) as usize]) {
                     error!("Couldn't write to file");
                 }
+                progress.advance(filesize - i);
                 i = filesize-1;
             }
         }
 
-        println!("done."); let _ = std::io::stdout().flush();
+        //compare against the sender's trailing digest to catch silent corruption
+        let mut expected_digest = [0u8; 32];
+        channel.receive_data(&mut expected_digest)?;
+        let actual_digest = hasher.finalize();
+
+        progress.clear();
+
+        if compare_byte_array(&actual_digest[..], &expected_digest) {
+            println!("Receive {}...done.", filename);
+        }
+        else {
+            println!("Receive {}...done, but integrity check failed!", filename);
+            drop(file);
+            if let Err(_) = std::fs::remove_file(&file_path) {
+                println!("Couldn't delete the corrupt file {}.", filename);
+            }
+        }
+        let _ = std::io::stdout().flush();
     }
+
+    return Ok(());
 }